@@ -25,6 +25,9 @@ use std::collections::HashMap;
 use std::io;
 
 const CLIENT: Token = Token(0);
+/// How many recent messages/topics we keep per room to replay as
+/// scrollback when an IRC client JOINs.
+const DEFAULT_BACKFILL_LEN: usize = 10;
 
 #[derive(Debug)]
 pub enum Event {
@@ -32,11 +35,20 @@ pub enum Event {
     Matrix(matrix::events::Event)
 }
 
+/// A user's last-known Matrix presence, mirrored onto IRC as away
+/// status.
+struct Presence {
+    state: matrix::events::PresenceState,
+    status_msg: Option<String>
+}
+
 pub struct Bridge {
     client: irc::streams::Client,
     matrix: matrix::client::Client,
     rooms: HashMap<matrix::model::RoomID, Room>,
     seen_events: Vec<matrix::model::EventID>,
+    presence: HashMap<matrix::model::UserID, Presence>,
+    handlers: Vec<Box<EventHandler>>,
 }
 
 impl Handler for Bridge {
@@ -67,111 +79,366 @@ impl Handler for Bridge {
 
 unsafe impl Sync for Bridge{}
 
+/// Turns an arbitrary display name into a legal, lowercase IRC channel
+/// token: `#` followed by alphanumerics/`-`/`_`, with everything else
+/// collapsed to a single `-`.
+fn slugify_channel(raw: &str) -> String {
+    let mut slug: String = raw.chars().map(|c| {
+        if c.is_alphanumeric() || c == '-' || c == '_' {
+            c.to_ascii_lowercase()
+        } else {
+            '-'
+        }
+    }).collect();
+    while slug.contains("--") {
+        slug = slug.replace("--", "-");
+    }
+    let slug = slug.trim_matches('-');
+    format!("#{}", if slug.is_empty() { "room" } else { slug })
+}
+
+/// The pieces of a `RoomEvent` worth replaying as scrollback.
+#[derive(Clone)]
+enum BufferedEntry {
+    Message(matrix::model::UserID, String),
+    Topic(matrix::model::UserID, String)
+}
+
+/// A bounded, de-duplicated, timestamp-ordered ring buffer of recent
+/// room events, used to backfill IRC clients that JOIN after the fact.
+struct MessageQueue {
+    capacity: usize,
+    events: Vec<(matrix::model::EventID, u64, BufferedEntry)>
+}
+
+impl MessageQueue {
+    fn new(capacity: usize) -> Self {
+        MessageQueue { capacity: capacity, events: vec![] }
+    }
+
+    fn push(&mut self, id: matrix::model::EventID, origin_server_ts: u64, entry: BufferedEntry) {
+        if self.events.iter().any(|&(ref seen, _, _)| seen == &id) {
+            return;
+        }
+        self.events.push((id, origin_server_ts, entry));
+        self.events.sort_by_key(|&(_, ts, _)| ts);
+        while self.events.len() > self.capacity {
+            self.events.remove(0);
+        }
+    }
+
+    fn iter(&self) -> ::std::slice::Iter<(matrix::model::EventID, u64, BufferedEntry)> {
+        self.events.iter()
+    }
+}
+
+/// Renders a millisecond Matrix timestamp as a short "N ago" string for
+/// prefixing replayed scrollback.
+fn relative_timestamp(origin_server_ts: u64) -> String {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64)
+        .unwrap_or(origin_server_ts);
+    let elapsed_ms = now_ms.saturating_sub(origin_server_ts);
+    let minutes = elapsed_ms / 60_000;
+    if minutes < 1 {
+        "just now".to_string()
+    } else if minutes < 60 {
+        format!("{}m ago", minutes)
+    } else {
+        format!("{}h ago", minutes / 60)
+    }
+}
+
+/// A room member as known from `m.room.member` state: who they are and
+/// their chosen display name, if any. `Room::members` only ever holds
+/// joined members (`Bridge::run_handlers` removes an entry outright on
+/// Leave), so there's no membership-state field worth tracking here;
+/// likewise there's no per-member power-level event surfaced to the
+/// bridge yet, so that isn't tracked either rather than carry a field
+/// that's always the same placeholder value.
+struct RoomMember {
+    user: matrix::model::UserID,
+    display_name: Option<String>
+}
+
+/// Turns a Matrix display name into a legal IRC nick: alphanumerics,
+/// `-`, `_`, `[` and `]` only, everything else dropped.
+fn slugify_nick(raw: &str) -> String {
+    let slug: String = raw.chars().filter(|c| {
+        c.is_alphanumeric() || *c == '-' || *c == '_' || *c == '[' || *c == ']'
+    }).collect();
+    if slug.is_empty() { "user".to_string() } else { slug }
+}
+
 struct Room {
     id: matrix::model::RoomID,
+    name: Option<String>,
     canonical_alias: Option<String>,
+    aliases: Vec<String>,
     join_rules: Option<String>,
-    members: Vec<matrix::model::UserID>,
-    pending_events: Vec<matrix::events::RoomEvent>
+    members: Vec<RoomMember>,
+    pending_events: Vec<matrix::events::RoomEvent>,
+    /// The slugified, deduplicated IRC channel we're currently exposing
+    /// this room as. `None` until a name, alias or join event has led
+    /// `Bridge::assign_channel_name` to claim one.
+    channel: Option<String>,
+    scrollback: MessageQueue
 }
 
 impl Room {
     fn new(id: matrix::model::RoomID) -> Self {
         Room {
             id: id,
+            name: None,
             canonical_alias: None,
+            aliases: vec![],
             join_rules: None,
             members: vec![],
-            pending_events: vec![]
+            pending_events: vec![],
+            channel: None,
+            scrollback: MessageQueue::new(DEFAULT_BACKFILL_LEN)
         }
     }
 
-    fn run_pending<F>(&mut self, mut callback: &mut F)
-            where F: FnMut(irc::protocol::Message) {
-        while let Some(evt) = self.pending_events.pop() {
-            self.handle_with_alias(evt, callback);
+    /// Buffers message/topic events into `scrollback` so they can be
+    /// replayed to clients that JOIN later. A no-op for any other event
+    /// kind.
+    fn record_scrollback(&mut self, id: matrix::model::EventID, origin_server_ts: u64,
+                          evt: &matrix::events::RoomEvent) {
+        match *evt {
+            matrix::events::RoomEvent::Message(ref user, ref text) =>
+                self.scrollback.push(id, origin_server_ts, BufferedEntry::Message(user.clone(), text.clone())),
+            matrix::events::RoomEvent::Topic(ref user, ref topic) =>
+                self.scrollback.push(id, origin_server_ts, BufferedEntry::Topic(user.clone(), topic.clone())),
+            _ => ()
         }
     }
 
-    fn handle_with_alias<F>(&mut self, evt: matrix::events::RoomEvent, mut callback: &mut F)
-            where F: FnMut(irc::protocol::Message) {
-        if self.canonical_alias != None {
-            match evt {
-                matrix::events::RoomEvent::Membership(user, matrix::events::MembershipAction::Join) => {
-                    callback(irc::protocol::Message {
-                        prefix: Some(format!("{}!{}@{}", user.nickname, user.nickname, user.homeserver)),
-                        command: irc::protocol::Command::Join,
-                        args: vec![self.canonical_alias.clone().unwrap()],
-                        suffix: None
-                    });
-                    self.members.push(user);
-                },
-                matrix::events::RoomEvent::Membership(user, matrix::events::MembershipAction::Leave) => {
-                    callback(irc::protocol::Message {
-                        prefix: Some(format!("{}!{}@{}", user.nickname, user.nickname, user.homeserver)),
-                        command: irc::protocol::Command::Part,
-                        args: vec![self.canonical_alias.clone().unwrap()],
-                        suffix: None
-                    });
-                    self.members.push(user);
-                },
-                matrix::events::RoomEvent::Membership(_, _) => (),
-                matrix::events::RoomEvent::Message(user, text) => {
-                    callback(irc::protocol::Message {
-                        prefix: Some(format!("{}!{}@{}", user.nickname, user.nickname, user.homeserver)),
-                        command: irc::protocol::Command::Privmsg,
-                        args: vec![self.canonical_alias.clone().unwrap()],
-                        suffix: Some(text)
-                    });
-                },
-                matrix::events::RoomEvent::Topic(user, topic) => {
-                    callback(irc::protocol::Message {
-                        prefix: Some(format!("{}!{}@{}", user.nickname, user.nickname, user.homeserver)),
-                        command: irc::protocol::Command::Topic,
-                        args: vec![self.canonical_alias.clone().unwrap()],
-                        suffix: Some(topic.clone())
-                    });
-                },
-                matrix::events::RoomEvent::CanonicalAlias(_) => unreachable!("canonical_alias should have been handled already!"),
-                _ => {
-                    warn!("Unhandled event {:?}", evt)
-                }
+    /// The Matrix display-name heuristic: `m.room.name`, then the
+    /// canonical alias, then the first published alias, then a name
+    /// derived from membership (excluding our own account, so a plain
+    /// 1:1 DM resolves to just the other party's name).
+    fn resolve_name(&self, own_user: Option<&matrix::model::UserID>) -> String {
+        if let Some(ref name) = self.name {
+            if !name.is_empty() {
+                return name.clone();
             }
-        } else {
-            self.pending_events.push(evt);
+        }
+        if let Some(ref alias) = self.canonical_alias {
+            return alias.clone();
+        }
+        if let Some(first) = self.aliases.get(0) {
+            return first.clone();
+        }
+        let others: Vec<usize> = self.members.iter().enumerate()
+            .filter(|&(_, m)| own_user.map_or(true, |me| m.user.nickname != me.nickname))
+            .map(|(i, _)| i)
+            .collect();
+        match others.len() {
+            0 => "Empty room".to_string(),
+            1 => self.member_display(others[0]),
+            n => format!("{} and {} others", self.member_display(others[0]), n - 1)
         }
     }
 
-    fn handle_event<F>(&mut self, evt: matrix::events::RoomEvent, mut callback: F)
-            where F: FnMut(irc::protocol::Message) {
+    fn member_display(&self, index: usize) -> String {
+        match self.members[index].display_name {
+            Some(ref name) => name.clone(),
+            None => self.members[index].user.nickname.clone()
+        }
+    }
+
+    /// The IRC-safe nick a member should appear as, preferring their
+    /// Matrix display name over their raw nickname.
+    fn member_nick(&self, user: &matrix::model::UserID) -> String {
+        let nicks = self.member_nicks();
+        match self.members.iter().position(|m| m.user.nickname == user.nickname) {
+            Some(index) => nicks[index].clone(),
+            None => user.nickname.clone()
+        }
+    }
+
+    /// The IRC-safe, cross-member-unique nicks of every current member,
+    /// in member order. Display names collapse to the same slug often
+    /// enough (two "Bob"s, or "Bob" vs "Bob!") that we disambiguate
+    /// with a `-2`, `-3`, ... suffix, the same strategy
+    /// `Bridge::assign_channel_name` uses for channel collisions.
+    fn member_nicks(&self) -> Vec<String> {
+        let mut assigned: Vec<String> = vec![];
+        for member in self.members.iter() {
+            let base = match member.display_name {
+                Some(ref name) => slugify_nick(name),
+                None => member.user.nickname.clone()
+            };
+            let mut candidate = base.clone();
+            let mut suffix = 1;
+            while assigned.iter().any(|n| n == &candidate) {
+                suffix += 1;
+                candidate = format!("{}-{}", base, suffix);
+            }
+            assigned.push(candidate);
+        }
+        assigned
+    }
+
+    fn upsert_member(&mut self, user: matrix::model::UserID, display_name: Option<String>) {
+        if let Some(existing) = self.members.iter_mut().find(|m| m.user.nickname == user.nickname) {
+            existing.user = user;
+            existing.display_name = display_name;
+            return;
+        }
+        self.members.push(RoomMember {
+            user: user,
+            display_name: display_name
+        });
+    }
+
+    fn remove_member(&mut self, user: &matrix::model::UserID) {
+        self.members.retain(|m| m.user.nickname != user.nickname);
+    }
+
+    /// Applies a room-state event (name/alias/membership bookkeeping),
+    /// returning:
+    /// - whether it may have changed the room's resolved name (so
+    ///   `Bridge` should re-run `assign_channel_name`), and
+    /// - the event itself, if it's one `Bridge` should run through the
+    ///   registered `EventHandler`s (directly, or queued in
+    ///   `pending_events` if we have no channel to forward to yet).
+    fn handle_event(&mut self, evt: matrix::events::RoomEvent) -> (bool, Option<matrix::events::RoomEvent>) {
         match evt {
             matrix::events::RoomEvent::CanonicalAlias(name) => {
-                let was_empty = self.canonical_alias == None;
-                self.canonical_alias = Some(name.clone());
-                if was_empty {
-                    self.run_pending(&mut callback);
-                }
+                self.canonical_alias = Some(name);
+                (true, None)
             },
-            matrix::events::RoomEvent::JoinRules(rules) =>
-                self.join_rules = Some(rules.clone()),
-            matrix::events::RoomEvent::Create => (),
+            matrix::events::RoomEvent::JoinRules(rules) => {
+                self.join_rules = Some(rules.clone());
+                (false, None)
+            },
+            matrix::events::RoomEvent::Create => (false, None),
             matrix::events::RoomEvent::Aliases(aliases) => {
-                let is_empty = self.canonical_alias == None;
-                if is_empty {
-                    self.canonical_alias = Some(aliases[0].clone());
-                    self.run_pending(&mut callback);
-                }
+                self.aliases = aliases;
+                (true, None)
+            },
+            matrix::events::RoomEvent::PowerLevels => (false, None),
+            matrix::events::RoomEvent::HistoryVisibility(_) => (false, None),
+            matrix::events::RoomEvent::Name(_, name) => {
+                self.name = if name.is_empty() { None } else { Some(name) };
+                (true, None)
             },
-            matrix::events::RoomEvent::PowerLevels => (),
-            matrix::events::RoomEvent::HistoryVisibility(_) => (),
-            matrix::events::RoomEvent::Name(_, _) => (),
-            matrix::events::RoomEvent::Avatar(_, _) => (),
+            matrix::events::RoomEvent::Avatar(_, _) => (false, None),
             matrix::events::RoomEvent::Unknown(unknown_type, json) => {
                 warn!("Unknown room event {}", unknown_type);
                 trace!("raw event: {:?}", json);
+                (false, None)
+            },
+            matrix::events::RoomEvent::Membership(ref user, matrix::events::MembershipAction::Join, ref display_name) => {
+                // Applied here, synchronously, rather than left for
+                // `Bridge::run_handlers` to pick up later: the room's
+                // member count has to be current *before* we resolve a
+                // membership-derived name below, or we name the room
+                // off a stale count. Once the room already has a
+                // channel we leave it alone on further joins/leaves, so
+                // membership churn alone never renames (PART/JOINs) a
+                // channel the IRC client is already in; only name and
+                // alias events do that.
+                self.upsert_member(user.clone(), display_name.clone());
+                (self.channel.is_none(), Some(evt))
+            },
+            matrix::events::RoomEvent::Membership(_, _, _) => (false, Some(evt)),
+            _ => (false, Some(evt))
+        }
+    }
+}
+
+/// Callback-based extension point for Matrix->IRC translation. `Bridge`
+/// invokes every registered handler, in order, for each decoded event;
+/// the default no-op bodies let a handler opt into only the callbacks
+/// it cares about (e.g. a command bot only needs `on_room_message`).
+pub trait EventHandler {
+    fn on_room_message(&mut self, _room: &Room, _user: &matrix::model::UserID, _text: &str,
+                        _emit: &mut FnMut(irc::protocol::Message)) {}
+
+    fn on_membership(&mut self, _room: &Room, _user: &matrix::model::UserID,
+                      _action: &matrix::events::MembershipAction, _emit: &mut FnMut(irc::protocol::Message)) {}
+
+    fn on_topic(&mut self, _room: &Room, _user: &matrix::model::UserID, _topic: &str,
+                _emit: &mut FnMut(irc::protocol::Message)) {}
+
+    fn on_presence(&mut self, _room: &Room, _user: &matrix::model::UserID, _state: &matrix::events::PresenceState,
+                    _status_msg: &Option<String>, _emit: &mut FnMut(irc::protocol::Message)) {}
+}
+
+/// The bridge's built-in handler: mirrors room messages, joins/leaves,
+/// topics and presence changes onto the connected IRC client, with
+/// members shown by their display-name-derived nick. Other membership
+/// actions are not forwarded. Always registered first so other handlers
+/// (command bots, loggers, ...) layer on top of normal IRC forwarding
+/// rather than replacing it.
+struct IrcForwarder;
+
+impl EventHandler for IrcForwarder {
+    fn on_room_message(&mut self, room: &Room, user: &matrix::model::UserID, text: &str,
+                        emit: &mut FnMut(irc::protocol::Message)) {
+        let channel = match room.channel { Some(ref c) => c.clone(), None => return };
+        emit(irc::protocol::Message {
+            prefix: Some(format!("{}!{}@{}", room.member_nick(user), user.nickname, user.homeserver)),
+            command: irc::protocol::Command::Privmsg,
+            args: vec![channel],
+            suffix: Some(text.to_string())
+        });
+    }
+
+    fn on_membership(&mut self, room: &Room, user: &matrix::model::UserID,
+                      action: &matrix::events::MembershipAction, emit: &mut FnMut(irc::protocol::Message)) {
+        let channel = match room.channel { Some(ref c) => c.clone(), None => return };
+        let prefix = Some(format!("{}!{}@{}", room.member_nick(user), user.nickname, user.homeserver));
+        match *action {
+            matrix::events::MembershipAction::Join => emit(irc::protocol::Message {
+                prefix: prefix, command: irc::protocol::Command::Join, args: vec![channel], suffix: None
+            }),
+            matrix::events::MembershipAction::Leave => emit(irc::protocol::Message {
+                prefix: prefix, command: irc::protocol::Command::Part, args: vec![channel], suffix: None
+            }),
+            _ => ()
+        }
+    }
+
+    fn on_topic(&mut self, room: &Room, user: &matrix::model::UserID, topic: &str,
+                emit: &mut FnMut(irc::protocol::Message)) {
+        let channel = match room.channel { Some(ref c) => c.clone(), None => return };
+        emit(irc::protocol::Message {
+            prefix: Some(format!("{}!{}@{}", room.member_nick(user), user.nickname, user.homeserver)),
+            command: irc::protocol::Command::Topic,
+            args: vec![channel],
+            suffix: Some(topic.to_string())
+        });
+    }
+
+    fn on_presence(&mut self, room: &Room, user: &matrix::model::UserID, state: &matrix::events::PresenceState,
+                    status_msg: &Option<String>, emit: &mut FnMut(irc::protocol::Message)) {
+        // `AWAY` is a client->server self-report in real IRC, not
+        // something a server relays about a third party to a channel —
+        // so presence changes are announced with a plain NOTICE here.
+        // `WHOIS` is the actual away-status query a client would use
+        // for a given nick; `Bridge::send_whois` answers that from the
+        // same presence data.
+        let channel = match room.channel { Some(ref c) => c.clone(), None => return };
+        let is_away = *state != matrix::events::PresenceState::Online;
+        let text = if is_away {
+            match *status_msg {
+                Some(ref msg) => format!("is now away: {}", msg),
+                None => "is now away".to_string()
             }
-            _ => self.handle_with_alias(evt, &mut callback)
+        } else {
+            "is back".to_string()
         };
+        emit(irc::protocol::Message {
+            prefix: Some(format!("{}!{}@{}", room.member_nick(user), user.nickname, user.homeserver)),
+            command: irc::protocol::Command::Notice,
+            args: vec![channel],
+            suffix: Some(text)
+        });
     }
 }
 
@@ -190,10 +457,8 @@ impl Bridge {
     pub fn room_from_irc(&mut self, id: &String) -> Option<&mut Room> {
         let mut room_id: Option<matrix::model::RoomID> = None;
         for (_, r) in self.rooms.iter_mut() {
-            if let Some(ref alias) = r.canonical_alias {
-                if alias == id {
-                    room_id = Some(r.id.clone())
-                }
+            if r.channel.as_ref() == Some(id) {
+                room_id = Some(r.id.clone())
             }
         }
         match room_id {
@@ -202,13 +467,309 @@ impl Bridge {
         }
     }
 
+    /// Resolves a room's current display name, slugifies it, and claims
+    /// a unique IRC channel token for it, growing a `-2`, `-3`, ...
+    /// suffix if another room already resolved to the same name.
+    /// Returns the old and new channel when this actually renames a
+    /// channel that was already assigned, so the caller can tell the
+    /// connected client about it.
+    fn assign_channel_name(&mut self, room_id: &matrix::model::RoomID) -> Option<(String, String)> {
+        let raw_name = match self.rooms.get(room_id) {
+            Some(room) => room.resolve_name(self.matrix.uid.as_ref()),
+            None => return None
+        };
+        let base = slugify_channel(&raw_name);
+        let mut candidate = base.clone();
+        let mut suffix = 1;
+        while self.rooms.iter().any(|(id, r)| id != room_id && r.channel.as_ref() == Some(&candidate)) {
+            suffix += 1;
+            candidate = format!("{}-{}", base, suffix);
+        }
+        let room = match self.rooms.get_mut(room_id) {
+            Some(room) => room,
+            None => return None
+        };
+        if room.channel.as_ref() == Some(&candidate) {
+            return None;
+        }
+        let old_channel = room.channel.take();
+        room.channel = Some(candidate.clone());
+        old_channel.map(|old| (old, candidate))
+    }
+
+    /// Forwards a decoded room event to the registered `EventHandler`s
+    /// if we already have a channel to forward to, otherwise queues it
+    /// in `pending_events` until one shows up.
+    fn dispatch_room_event<F>(&mut self, room_id: &matrix::model::RoomID, evt: matrix::events::RoomEvent, emit: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        let channel_ready = self.rooms.get(room_id).map_or(false, |r| r.channel.is_some());
+        if channel_ready {
+            self.run_handlers(room_id, evt, emit);
+        } else if let Some(room) = self.rooms.get_mut(room_id) {
+            room.pending_events.push(evt);
+        }
+    }
+
+    /// Drains a room's `pending_events` (queued while it had no channel
+    /// yet) through the handler chain, now that it does.
+    fn flush_pending<F>(&mut self, room_id: &matrix::model::RoomID, emit: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        let pending = match self.rooms.get_mut(room_id) {
+            Some(room) => ::std::mem::replace(&mut room.pending_events, vec![]),
+            None => return
+        };
+        for evt in pending {
+            self.run_handlers(room_id, evt, emit);
+        }
+    }
+
+    /// Runs every registered `EventHandler` against a room event in
+    /// order, then drops the member from the room if the event was a
+    /// Leave.
+    fn run_handlers<F>(&mut self, room_id: &matrix::model::RoomID, evt: matrix::events::RoomEvent, emit: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        // Joins are already applied by `Room::handle_event`, before the
+        // channel name is resolved. Leaves are applied after dispatch,
+        // so handlers can still look up the leaving member's display
+        // name while building the PART message.
+        let leaving = match evt {
+            matrix::events::RoomEvent::Membership(ref user, matrix::events::MembershipAction::Leave, _) => Some(user.clone()),
+            _ => None
+        };
+        let Bridge { ref rooms, ref mut handlers, .. } = *self;
+        let room = match rooms.get(room_id) {
+            Some(room) => room,
+            None => return
+        };
+        match evt {
+            matrix::events::RoomEvent::Membership(user, action, _) => {
+                for handler in handlers.iter_mut() {
+                    handler.on_membership(room, &user, &action, emit);
+                }
+            },
+            matrix::events::RoomEvent::Message(user, text) => {
+                for handler in handlers.iter_mut() {
+                    handler.on_room_message(room, &user, &text, emit);
+                }
+            },
+            matrix::events::RoomEvent::Topic(user, topic) => {
+                for handler in handlers.iter_mut() {
+                    handler.on_topic(room, &user, &topic, emit);
+                }
+            },
+            matrix::events::RoomEvent::CanonicalAlias(_) => unreachable!("channel should have been handled already!"),
+            _ => warn!("Unhandled event {:?}", evt)
+        }
+        if let Some(user) = leaving {
+            if let Some(room) = self.rooms.get_mut(room_id) {
+                room.remove_member(&user);
+            }
+        }
+    }
+
+    /// When a room's scrollback buffer holds fewer than
+    /// `DEFAULT_BACKFILL_LEN` entries, fetches one page of up to that
+    /// many recent events via `/messages`, so a freshly-joined room isn't
+    /// limited to whatever has trickled in over `/sync`. No further
+    /// pages are requested, even if the buffer is still not full.
+    fn backfill_room(&mut self, room_id: &matrix::model::RoomID) {
+        let need_more = self.rooms.get(room_id).map_or(false, |r| r.scrollback.events.len() < DEFAULT_BACKFILL_LEN);
+        if !need_more {
+            return;
+        }
+        match self.matrix.messages(room_id, DEFAULT_BACKFILL_LEN) {
+            Ok(events) => {
+                for evt in events {
+                    if let (Some(id), matrix::events::EventData::Room(evt_room_id, room_event)) = (evt.id, evt.data) {
+                        if &evt_room_id == room_id {
+                            self.room_from_matrix(room_id).record_scrollback(id, evt.origin_server_ts, &room_event);
+                        }
+                    }
+                }
+            },
+            Err(err) => warn!("Could not backfill room history: {:?}", err)
+        }
+    }
+
+    /// Replays a room's buffered scrollback to the bridge's IRC client:
+    /// messages as PRIVMSGs prefixed with how long ago they were sent,
+    /// topics as TOPICs with the topic text unchanged.
+    fn replay_scrollback(&mut self, room_id: &matrix::model::RoomID) {
+        let room = match self.rooms.get(room_id) {
+            Some(room) => room,
+            None => return
+        };
+        let channel = match room.channel.clone() {
+            Some(channel) => channel,
+            None => return
+        };
+        for &(_, ts, ref entry) in room.scrollback.iter() {
+            let msg = match *entry {
+                BufferedEntry::Message(ref user, ref text) => irc::protocol::Message {
+                    prefix: Some(format!("{}!{}@{}", room.member_nick(user), user.nickname, user.homeserver)),
+                    command: irc::protocol::Command::Privmsg,
+                    args: vec![channel.clone()],
+                    suffix: Some(format!("[{}] {}", relative_timestamp(ts), text))
+                },
+                BufferedEntry::Topic(ref user, ref topic) => irc::protocol::Message {
+                    prefix: Some(format!("{}!{}@{}", room.member_nick(user), user.nickname, user.homeserver)),
+                    command: irc::protocol::Command::Topic,
+                    args: vec![channel.clone()],
+                    suffix: Some(topic.clone())
+                }
+            };
+            if let Err(err) = self.client.send(&msg) {
+                warn!("Could not replay scrollback: {:?}", err);
+            }
+        }
+    }
+
+    /// Answers `NAMES` with an RPL_NAMREPLY/RPL_ENDOFNAMES pair listing
+    /// the room's members by their display-name-derived nick.
+    fn send_names(&mut self, channel: &String) -> io::Result<usize> {
+        let nicks = match self.room_from_irc(channel) {
+            Some(room) => room.member_nicks(),
+            None => vec![]
+        };
+        let me = self.client.nickname().to_string();
+        self.client.send(&irc::protocol::Message {
+            prefix: None,
+            command: irc::protocol::Command::Reply(353),
+            args: vec![me.clone(), "=".to_string(), channel.clone()],
+            suffix: Some(nicks.join(" "))
+        }).and_then(|_| self.client.send(&irc::protocol::Message {
+            prefix: None,
+            command: irc::protocol::Command::Reply(366),
+            args: vec![me, channel.clone()],
+            suffix: Some("End of /NAMES list.".to_string())
+        }))
+    }
+
+    /// Answers `WHO` with an RPL_WHOREPLY per member (display-name-derived
+    /// nick, real nickname as ident/realname) and a trailing RPL_ENDOFWHO.
+    fn send_who(&mut self, channel: &String) -> io::Result<usize> {
+        let members: Vec<(String, matrix::model::UserID)> = match self.room_from_irc(channel) {
+            Some(room) => room.members.iter().map(|m| (room.member_nick(&m.user), m.user.clone())).collect(),
+            None => vec![]
+        };
+        let me = self.client.nickname().to_string();
+        let mut res: Option<io::Result<usize>> = None;
+        for (nick, user) in members {
+            let msg = irc::protocol::Message {
+                prefix: None,
+                command: irc::protocol::Command::Reply(352),
+                args: vec![me.clone(), channel.clone(), user.nickname.clone(), user.homeserver.clone(),
+                           user.homeserver.clone(), nick.clone(), "H".to_string()],
+                suffix: Some(format!("0 {}", user.nickname))
+            };
+            res = Some(match res {
+                None => self.client.send(&msg),
+                Some(r) => r.and(self.client.send(&msg))
+            });
+        }
+        let end = self.client.send(&irc::protocol::Message {
+            prefix: None,
+            command: irc::protocol::Command::Reply(315),
+            args: vec![me, channel.clone()],
+            suffix: Some("End of /WHO list.".to_string())
+        });
+        match res {
+            None => end,
+            Some(r) => r.and(end)
+        }
+    }
+
+    /// Answers `WHOIS` for a nick we recognize as a current room member
+    /// with RPL_WHOISUSER and, if their last-known Matrix presence was
+    /// away, RPL_AWAY; always terminated by RPL_ENDOFWHOIS. This is how
+    /// a real IRC client actually learns a nick's away status.
+    fn send_whois(&mut self, nick: &str) -> io::Result<usize> {
+        let member = self.rooms.values().filter_map(|room| {
+            room.members.iter().zip(room.member_nicks())
+                .find(|&(_, ref n)| n == nick)
+                .map(|(m, _)| m.user.clone())
+        }).next();
+        let me = self.client.nickname().to_string();
+        let mut res: Option<io::Result<usize>> = None;
+        if let Some(ref user) = member {
+            res = Some(self.client.send(&irc::protocol::Message {
+                prefix: None,
+                command: irc::protocol::Command::Reply(311),
+                args: vec![me.clone(), nick.to_string(), user.nickname.clone(), user.homeserver.clone(), "*".to_string()],
+                suffix: Some(user.nickname.clone())
+            }));
+            let away = self.presence.get(user).and_then(|presence| {
+                if presence.state != matrix::events::PresenceState::Online {
+                    Some(presence.status_msg.clone().unwrap_or("Away".to_string()))
+                } else {
+                    None
+                }
+            });
+            if let Some(away_msg) = away {
+                res = Some(res.unwrap().and(self.client.send(&irc::protocol::Message {
+                    prefix: None,
+                    command: irc::protocol::Command::Reply(301),
+                    args: vec![me.clone(), nick.to_string()],
+                    suffix: Some(away_msg)
+                })));
+            }
+        }
+        let end = self.client.send(&irc::protocol::Message {
+            prefix: None,
+            command: irc::protocol::Command::Reply(318),
+            args: vec![me, nick.to_string()],
+            suffix: Some("End of /WHOIS list.".to_string())
+        });
+        match res {
+            None => end,
+            Some(r) => r.and(end)
+        }
+    }
+
     pub fn new(client: irc::streams::Client, url: &str) -> Self {
         Bridge {
             client: client,
             matrix: matrix::client::Client::new(url),
             rooms: HashMap::new(),
-            seen_events: vec![]
+            seen_events: vec![],
+            presence: HashMap::new(),
+            handlers: vec![Box::new(IrcForwarder)]
+        }
+    }
+
+    /// Registers an additional `EventHandler`, run after every
+    /// previously-registered one. Use this for command-bot behavior,
+    /// logging, metrics, etc. without touching the core dispatch loop.
+    pub fn add_handler(&mut self, handler: Box<EventHandler>) {
+        self.handlers.push(handler);
+    }
+
+    /// Records a user's presence transition and, if their away-ness
+    /// changed, runs the handler chain's `on_presence` against every
+    /// room they're a member of.
+    fn handle_presence(&mut self, user: matrix::model::UserID,
+                        state: matrix::events::PresenceState,
+                        status_msg: Option<String>) -> Vec<irc::protocol::Message> {
+        let was_away = self.presence.get(&user).map_or(false, |p| {
+            p.state != matrix::events::PresenceState::Online
+        });
+        let is_away = state != matrix::events::PresenceState::Online;
+        let mut messages = vec![];
+        if is_away != was_away {
+            let Bridge { ref rooms, ref mut handlers, .. } = *self;
+            for room in rooms.values() {
+                if room.members.iter().any(|m| m.user.nickname == user.nickname) {
+                    for handler in handlers.iter_mut() {
+                        handler.on_presence(room, &user, &state, &status_msg, &mut |m: irc::protocol::Message| messages.push(m));
+                    }
+                }
+            }
         }
+        self.presence.insert(user, Presence {
+            state: state,
+            status_msg: status_msg
+        });
+        messages
     }
 
     pub fn run(&mut self) {
@@ -226,14 +787,52 @@ impl Bridge {
         if !duplicate {
             let mut messages: Vec<irc::protocol::Message> = vec![];
             {
-                let append_msg = |msg: irc::protocol::Message| {
+                let mut append_msg = |msg: irc::protocol::Message| {
                     messages.push(msg);
                 };
                 match evt.data {
                     matrix::events::EventData::Room(room_id, room_event) => {
-                        self.room_from_matrix(&room_id).handle_event(room_event, append_msg);
+                        let had_channel = self.rooms.get(&room_id).map_or(false, |r| r.channel.is_some());
+                        if let Some(ref id) = evt.id {
+                            self.room_from_matrix(&room_id).record_scrollback(id.clone(), evt.origin_server_ts, &room_event);
+                        }
+                        let (name_changed, to_dispatch) = self.room_from_matrix(&room_id).handle_event(room_event);
+                        if name_changed {
+                            if let Some((old_channel, new_channel)) = self.assign_channel_name(&room_id) {
+                                // The channel the client already joined
+                                // got renamed out from under it (e.g. the
+                                // room picked up an explicit name/alias).
+                                // Part the old token and join the new one
+                                // so the client follows along instead of
+                                // silently losing the room.
+                                let nick = Some(self.client.nickname().to_string());
+                                append_msg(irc::protocol::Message {
+                                    prefix: nick.clone(),
+                                    command: irc::protocol::Command::Part,
+                                    args: vec![old_channel],
+                                    suffix: Some("channel renamed".to_string())
+                                });
+                                append_msg(irc::protocol::Message {
+                                    prefix: nick,
+                                    command: irc::protocol::Command::Join,
+                                    args: vec![new_channel],
+                                    suffix: None
+                                });
+                            }
+                        }
+                        if let Some(dispatch_evt) = to_dispatch {
+                            self.dispatch_room_event(&room_id, dispatch_evt, &mut append_msg);
+                        }
+                        if name_changed && !had_channel {
+                            self.flush_pending(&room_id, &mut append_msg);
+                        }
                     },
                     matrix::events::EventData::Typing(_) => (),
+                    matrix::events::EventData::Presence(user, state, status_msg) => {
+                        for msg in self.handle_presence(user, state, status_msg) {
+                            append_msg(msg);
+                        }
+                    },
                     _ => warn!("Unhandled {}", evt.data.type_str())
                 }
             }
@@ -322,7 +921,28 @@ impl Bridge {
                             };
                         },
                         Command::Join => {
-                            self.client.join(&message.args[0]).expect("Could not send JOIN");
+                            let channel = message.args[0].clone();
+                            self.client.join(&channel).expect("Could not send JOIN");
+                            let room_id = self.room_from_irc(&channel).map(|room| room.id.clone());
+                            if let Some(room_id) = room_id {
+                                self.backfill_room(&room_id);
+                                self.replay_scrollback(&room_id);
+                            }
+                        },
+                        Command::Names => {
+                            if let Some(channel) = message.args.get(0).cloned() {
+                                self.send_names(&channel).expect("Could not send NAMES reply");
+                            }
+                        },
+                        Command::Who => {
+                            if let Some(channel) = message.args.get(0).cloned() {
+                                self.send_who(&channel).expect("Could not send WHO reply");
+                            }
+                        },
+                        Command::Whois => {
+                            if let Some(nick) = message.args.get(0).cloned() {
+                                self.send_whois(&nick).expect("Could not send WHOIS reply");
+                            }
                         },
                         Command::Ping => {
                             self.client.pong().expect("Could not send PONG");
@@ -354,3 +974,96 @@ impl Bridge {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(nickname: &str) -> matrix::model::UserID {
+        matrix::model::UserID { nickname: nickname.to_string(), homeserver: "example.org".to_string() }
+    }
+
+    fn event_id(raw: &str) -> matrix::model::EventID {
+        matrix::model::EventID(raw.to_string())
+    }
+
+    #[test]
+    fn message_queue_evicts_oldest_past_capacity() {
+        let mut queue = MessageQueue::new(2);
+        let (a, b, c) = (event_id("a"), event_id("b"), event_id("c"));
+        queue.push(a.clone(), 1, BufferedEntry::Message(user("alice"), "one".to_string()));
+        queue.push(b.clone(), 2, BufferedEntry::Message(user("alice"), "two".to_string()));
+        queue.push(c.clone(), 3, BufferedEntry::Message(user("alice"), "three".to_string()));
+        assert_eq!(queue.iter().count(), 2);
+        assert!(!queue.iter().any(|&(ref id, _, _)| id == &a));
+        assert!(queue.iter().any(|&(ref id, _, _)| id == &b));
+        assert!(queue.iter().any(|&(ref id, _, _)| id == &c));
+    }
+
+    #[test]
+    fn message_queue_dedupes_by_event_id() {
+        let mut queue = MessageQueue::new(5);
+        let id = event_id("a");
+        queue.push(id.clone(), 1, BufferedEntry::Message(user("alice"), "one".to_string()));
+        queue.push(id.clone(), 1, BufferedEntry::Message(user("alice"), "one, again".to_string()));
+        assert_eq!(queue.iter().count(), 1);
+    }
+
+    #[test]
+    fn message_queue_orders_by_timestamp_even_if_pushed_out_of_order() {
+        let mut queue = MessageQueue::new(5);
+        let (a, b) = (event_id("a"), event_id("b"));
+        queue.push(b.clone(), 2, BufferedEntry::Message(user("alice"), "two".to_string()));
+        queue.push(a.clone(), 1, BufferedEntry::Message(user("alice"), "one".to_string()));
+        let ids: Vec<matrix::model::EventID> = queue.iter().map(|&(ref id, _, _)| id.clone()).collect();
+        assert!(ids == vec![a, b]);
+    }
+
+    fn room() -> Room {
+        Room::new(matrix::model::RoomID("!room:example.org".to_string()))
+    }
+
+    #[test]
+    fn resolve_name_prefers_explicit_name_over_aliases() {
+        let mut room = room();
+        room.name = Some("Water Cooler".to_string());
+        room.canonical_alias = Some("#elsewhere:example.org".to_string());
+        room.aliases = vec!["#first:example.org".to_string()];
+        assert_eq!(room.resolve_name(None), "Water Cooler");
+    }
+
+    #[test]
+    fn resolve_name_prefers_canonical_alias_over_first_alias() {
+        let mut room = room();
+        room.canonical_alias = Some("#canonical:example.org".to_string());
+        room.aliases = vec!["#first:example.org".to_string()];
+        assert_eq!(room.resolve_name(None), "#canonical:example.org");
+    }
+
+    #[test]
+    fn resolve_name_falls_back_to_first_alias() {
+        let mut room = room();
+        room.aliases = vec!["#first:example.org".to_string(), "#second:example.org".to_string()];
+        assert_eq!(room.resolve_name(None), "#first:example.org");
+    }
+
+    #[test]
+    fn resolve_name_derives_from_membership_excluding_self() {
+        let me = user("bridge-bot");
+        let mut room = room();
+        room.upsert_member(me.clone(), None);
+        assert_eq!(room.resolve_name(Some(&me)), "Empty room");
+
+        room.upsert_member(user("alice"), Some("Alice".to_string()));
+        assert_eq!(room.resolve_name(Some(&me)), "Alice");
+
+        room.upsert_member(user("bob"), Some("Bob".to_string()));
+        assert_eq!(room.resolve_name(Some(&me)), "Alice and 1 others");
+    }
+
+    #[test]
+    fn resolve_name_empty_room_has_no_members() {
+        let room = room();
+        assert_eq!(room.resolve_name(None), "Empty room");
+    }
+}
+